@@ -2,12 +2,21 @@
 //! and dropped as soon as its not needed anymore.
 //! It requires the `lazy_static` macro to be imported.
 //!
+//! # `no_std` support
+//!
+//! Enabling the `no_std` feature makes this crate `#![no_std]`. It swaps
+//! `std::sync::{Arc, Weak, Mutex}` for `alloc::sync::{Arc, Weak}` and
+//! [`spin::Mutex`](https://docs.rs/spin), mirroring the way `lazy_static` offers a
+//! `spin_no_std` feature for the same environments. You'll need an allocator and a
+//! `lazy_static` built with its own `spin_no_std` feature enabled. The public
+//! behavior of the generated accessor is unchanged.
+//!
 //! # Example
 //!
 //! ```rust
 //! #[macro_use] extern crate lazy_static;
 //! #[macro_use] extern crate weak_static;
-//! 
+//!
 //! struct Foo;
 //!
 //! impl Foo {
@@ -22,7 +31,7 @@
 //!         println!("drop");
 //!     }
 //! }
-//! 
+//!
 //! weak_static! {
 //!     static FOO: Foo = Foo::new();
 //! }
@@ -33,7 +42,7 @@
 //!         let _foo2 = FOO();
 //!         let _foo3 = FOO();
 //!     }
-//!     
+//!
 //!     {
 //!         let _foo4 = FOO();
 //!         let _foo5 = FOO();
@@ -54,29 +63,451 @@
 //! The `new` prints corresponds to the `FOO()` calls of `_foo1` and `_foo4`.
 //! The `drop` prints correspond to the last FOO reference being dropped.
 //!
+//! # `loom` support
+//!
+//! Building with `--cfg loom` (as `loom`-driven tests do) swaps in `loom::sync::Mutex`
+//! and `loom::lazy_static!` in place of their `std` counterparts. `loom` has no `Weak`
+//! (and its `Arc` has no `downgrade`), so `Arc`/`Weak` stay `std`'s even under
+//! `--cfg loom`: only the `Mutex` locking/double-check dance in the generated accessor
+//! is exhaustively checked across every thread interleaving loom permits, not the
+//! `Arc` strong-count races that dance is meant to avoid.
+//!
+//! Just like `lazy_static!`, a single invocation can declare several statics at
+//! once, and each one can carry doc comments, other attributes and a visibility
+//! modifier that gets applied to the generated accessor function:
+//!
+//! ```rust
+//! # #[macro_use] extern crate lazy_static;
+//! # #[macro_use] extern crate weak_static;
+//! # struct Foo; struct Bar;
+//! # impl Foo { fn new() -> Self { Foo } }
+//! # impl Bar { fn new() -> Self { Bar } }
+//! weak_static! {
+//!     /// Lazily created, refcounted `Foo`.
+//!     pub static FOO: Foo = Foo::new();
+//!
+//!     pub(crate) static BAR: Bar = Bar::new();
+//! }
+//! # fn main() {}
+//! ```
+//!
+//! # Keyed caches
+//!
+//! [`weak_static_map!`] declares an accessor parameterized by a key, for per-key
+//! interning instead of a single slot. It requires `std` (it's backed by
+//! `std::collections::HashMap`), so the example below only runs when the `no_std`
+//! feature is disabled:
+#![cfg_attr(
+    not(feature = "no_std"),
+    doc = r#"
+```rust
+# #[macro_use] extern crate lazy_static;
+# #[macro_use] extern crate weak_static;
+struct Connection(String);
+
+weak_static_map! {
+    static CONNECTION(addr: String): Connection = Connection(addr);
+}
+
+fn main() {
+    let a = CONNECTION("127.0.0.1:1234".to_string());
+    let b = CONNECTION("127.0.0.1:1234".to_string());
+    assert!(::std::sync::Arc::ptr_eq(&a, &b));
+}
+```
+"#
+)]
+#![cfg_attr(
+    feature = "no_std",
+    doc = r#"
+```rust,ignore
+// `weak_static_map!` needs `std`, so this example is `ignore`d under `no_std`.
+# #[macro_use] extern crate lazy_static;
+# #[macro_use] extern crate weak_static;
+struct Connection(String);
+
+weak_static_map! {
+    static CONNECTION(addr: String): Connection = Connection(addr);
+}
+
+fn main() {
+    let a = CONNECTION("127.0.0.1:1234".to_string());
+    let b = CONNECTION("127.0.0.1:1234".to_string());
+    assert!(::std::sync::Arc::ptr_eq(&a, &b));
+}
+```
+"#
+)]
+//!
+
+#![cfg_attr(feature = "no_std", no_std)]
+
+#[cfg(feature = "no_std")]
+extern crate alloc;
+
+#[cfg(feature = "no_std")]
+extern crate spin;
 
+// Our own tests invoke `weak_static!`/`weak_static_map!` the same way a downstream
+// consumer would, so they need the same `#[macro_use] extern crate lazy_static;` the
+// crate docs ask consumers to add. Under `--cfg loom` the macros expand to
+// `loom::lazy_static!` instead, and under `no_std` none of the plain test modules
+// below are compiled at all, so this import is only needed for the plain, std test runs.
+#[cfg(all(test, not(loom), not(feature = "no_std")))]
+#[macro_use]
+extern crate lazy_static;
+
+/// Synchronization primitives and the shared get-or-init logic used by the generated
+/// accessors, factored out here so `weak_static!`/`weak_static_map!` don't have to
+/// hardcode `std` vs. `alloc`/`spin`/`loom` paths or repeat the double-checked-locking
+/// dance at every call site.
+#[doc(hidden)]
+pub mod __sync {
+    // `loom` has no `Weak` (and its `Arc` has no `downgrade`), so it only ever
+    // replaces `Mutex` here; `Arc`/`Weak` stay `std`/`alloc` even under `--cfg loom`.
+    #[cfg(not(feature = "no_std"))]
+    pub use std::sync::{Arc, Weak};
+    #[cfg(feature = "no_std")]
+    pub use alloc::sync::{Arc, Weak};
+
+    #[cfg(all(not(feature = "no_std"), not(loom)))]
+    pub use std::sync::Mutex;
+    #[cfg(all(not(feature = "no_std"), loom))]
+    pub use loom::sync::Mutex;
+    #[cfg(feature = "no_std")]
+    pub use spin::Mutex;
+
+    #[cfg(feature = "no_std")]
+    fn lock<T>(mutex: &Mutex<T>) -> impl core::ops::DerefMut<Target = T> + '_ {
+        mutex.lock()
+    }
+
+    #[cfg(not(feature = "no_std"))]
+    fn lock<T>(mutex: &Mutex<T>) -> impl core::ops::DerefMut<Target = T> + '_ {
+        mutex.lock().unwrap()
+    }
+
+    /// The get-or-init dance shared by every `weak_static!` accessor: try to upgrade
+    /// the cached `Weak`, and if that fails, build a new value *without* holding the
+    /// lock (so a reentrant `init` can't deadlock), then re-check before installing it.
+    pub fn get_or_init<T>(cell: &Mutex<Weak<T>>, init: impl FnOnce() -> T) -> Arc<T> {
+        let value = lock(cell);
+
+        if let Some(existing) = value.upgrade() {
+            return existing;
+        }
+
+        drop(value);
+
+        let new_value = Arc::new(init());
+
+        let mut value = lock(cell);
+
+        match value.upgrade() {
+            Some(existing) => existing,
+            None => {
+                *value = Arc::downgrade(&new_value);
+                new_value
+            }
+        }
+    }
+
+    /// Number of entries (live or dead) a `weak_static_map!` map is allowed to reach
+    /// before [`get_or_init_keyed`] sweeps dead (no-longer-upgradeable) entries on its
+    /// next insert.
+    #[cfg(not(feature = "no_std"))]
+    pub(crate) const PRUNE_THRESHOLD: usize = 64;
+
+    /// The keyed counterpart of [`get_or_init`] used by `weak_static_map!`: looks up
+    /// `key` in the map, and on a miss builds a new value without holding the lock,
+    /// pruning dead entries once the map grows past a threshold.
+    ///
+    /// Backed by `std::collections::HashMap`, so unlike `get_or_init` this isn't
+    /// available under the `no_std` feature.
+    #[cfg(not(feature = "no_std"))]
+    pub fn get_or_init_keyed<K, V>(
+        map: &Mutex<::std::collections::HashMap<K, Weak<V>>>,
+        key: K,
+        init: impl FnOnce() -> V,
+    ) -> Arc<V>
+    where
+        K: Eq + ::std::hash::Hash + Clone,
+    {
+        let values = lock(map);
+
+        if let Some(existing) = values.get(&key).and_then(Weak::upgrade) {
+            return existing;
+        }
+
+        drop(values);
+
+        let new_value = Arc::new(init());
+
+        let mut values = lock(map);
+
+        match values.get(&key).and_then(Weak::upgrade) {
+            Some(existing) => existing,
+            None => {
+                if values.len() >= PRUNE_THRESHOLD {
+                    values.retain(|_, weak| weak.strong_count() > 0);
+                }
+
+                values.insert(key, Arc::downgrade(&new_value));
+
+                new_value
+            }
+        }
+    }
+}
+
+/// Declares one or more weak-backed lazily created statics.
+///
+/// Each declaration looks like `[$(#[$attr])*] [pub[(...)]] static $IDENT: $TYP = $INIT;`
+/// and expands to a function `$IDENT() -> $crate::__sync::Arc<$TYP>` carrying the given
+/// attributes and visibility. See the crate-level docs for the full semantics.
 #[macro_export]
 macro_rules! weak_static {
-    (static $ident:ident : $typ:ty = $init:expr; ) => (
+    () => ();
+
+    (
+        $(#[$attr:meta])*
+        static $ident:ident : $typ:ty = $init:expr; $($rest:tt)*
+    ) => (
+        weak_static!(@make () $(#[$attr])* static $ident : $typ = $init;);
+        weak_static!($($rest)*);
+    );
+
+    (
+        $(#[$attr:meta])*
+        pub static $ident:ident : $typ:ty = $init:expr; $($rest:tt)*
+    ) => (
+        weak_static!(@make (pub) $(#[$attr])* static $ident : $typ = $init;);
+        weak_static!($($rest)*);
+    );
+
+    (
+        $(#[$attr:meta])*
+        pub ($($vis:tt)+) static $ident:ident : $typ:ty = $init:expr; $($rest:tt)*
+    ) => (
+        weak_static!(@make (pub ($($vis)+)) $(#[$attr])* static $ident : $typ = $init;);
+        weak_static!($($rest)*);
+    );
+
+    (@make ($($vis:tt)*) $(#[$attr:meta])* static $ident:ident : $typ:ty = $init:expr;) => (
+        $(#[$attr])*
         #[allow(non_snake_case)]
-        fn $ident() -> ::std::sync::Arc<$typ> {
+        $($vis)* fn $ident() -> $crate::__sync::Arc<$typ> {
             #[warn(non_snake_case)]
             {
+                #[cfg(loom)]
+                loom::lazy_static! {
+                    static ref VALUE: $crate::__sync::Mutex<$crate::__sync::Weak<$typ>> =
+                        $crate::__sync::Mutex::new($crate::__sync::Weak::new());
+                }
+                #[cfg(not(loom))]
                 lazy_static! {
-                    static ref VALUE: ::std::sync::Mutex<::std::sync::Weak<$typ>> =
-                        ::std::default::Default::default();
+                    static ref VALUE: $crate::__sync::Mutex<$crate::__sync::Weak<$typ>> =
+                        Default::default();
                 }
-                
-                let mut value = VALUE.lock().unwrap();
-                
-                value.upgrade().unwrap_or_else(|| {
-                    let new_value = ::std::sync::Arc::new($init);
-
-                    *value = ::std::sync::Arc::downgrade(&new_value);
-                    
-                    new_value
-                })
+
+                $crate::__sync::get_or_init(&VALUE, || $init)
             }
         }
     )
 }
+
+/// Declares one or more keyed weak-value caches, interning by key the same way
+/// `weak_static!` interns a single value.
+///
+/// Each declaration looks like
+/// `[$(#[$attr])*] [pub[(...)]] static $IDENT($key: $KEY): $TYP = $INIT;` and expands
+/// to a function `$IDENT($key: $KEY) -> $crate::__sync::Arc<$TYP>` backed by a
+/// `Mutex<HashMap<$KEY, Weak<$TYP>>>`. Identical keys alive at the same time share one
+/// `Arc`; a key's entry is recreated once its last `Arc` is dropped. `$KEY` must be
+/// `Eq + Hash + Clone`, and `$INIT` may refer to the bound `$key`. The map is swept for
+/// dead entries (keys whose `Weak` no longer upgrades) whenever it grows past an
+/// internal threshold, so it doesn't grow unbounded with tombstones. Unlike
+/// `weak_static!`, this variant requires `std` (it's backed by
+/// `std::collections::HashMap`).
+#[macro_export]
+macro_rules! weak_static_map {
+    () => ();
+
+    (
+        $(#[$attr:meta])*
+        static $ident:ident ( $key:ident : $keyty:ty ) : $typ:ty = $init:expr; $($rest:tt)*
+    ) => (
+        weak_static_map!(@make () $(#[$attr])* static $ident ($key : $keyty) : $typ = $init;);
+        weak_static_map!($($rest)*);
+    );
+
+    (
+        $(#[$attr:meta])*
+        pub static $ident:ident ( $key:ident : $keyty:ty ) : $typ:ty = $init:expr; $($rest:tt)*
+    ) => (
+        weak_static_map!(@make (pub) $(#[$attr])* static $ident ($key : $keyty) : $typ = $init;);
+        weak_static_map!($($rest)*);
+    );
+
+    (
+        $(#[$attr:meta])*
+        pub ($($vis:tt)+) static $ident:ident ( $key:ident : $keyty:ty ) : $typ:ty = $init:expr; $($rest:tt)*
+    ) => (
+        weak_static_map!(@make (pub ($($vis)+)) $(#[$attr])* static $ident ($key : $keyty) : $typ = $init;);
+        weak_static_map!($($rest)*);
+    );
+
+    (@make ($($vis:tt)*) $(#[$attr:meta])* static $ident:ident ( $key:ident : $keyty:ty ) : $typ:ty = $init:expr;) => (
+        $(#[$attr])*
+        #[allow(non_snake_case)]
+        $($vis)* fn $ident($key: $keyty) -> $crate::__sync::Arc<$typ> {
+            #[warn(non_snake_case)]
+            {
+                #[cfg(loom)]
+                loom::lazy_static! {
+                    static ref VALUES: $crate::__sync::Mutex<
+                        ::std::collections::HashMap<$keyty, $crate::__sync::Weak<$typ>>
+                    > = $crate::__sync::Mutex::new(::std::collections::HashMap::new());
+                }
+                #[cfg(not(loom))]
+                lazy_static! {
+                    static ref VALUES: $crate::__sync::Mutex<
+                        ::std::collections::HashMap<$keyty, $crate::__sync::Weak<$typ>>
+                    > = Default::default();
+                }
+
+                // `$init` may consume `$key` by value (e.g. `= build(key)`), so clone
+                // it up front for the lookup/storage side of the cache.
+                let __key_for_storage = $key.clone();
+
+                $crate::__sync::get_or_init_keyed(&VALUES, __key_for_storage, || $init)
+            }
+        }
+    )
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::sync::atomic::{AtomicUsize, Ordering};
+    use loom::thread;
+
+    struct Tracked {
+        epoch: usize,
+    }
+
+    loom::lazy_static! {
+        static ref NEXT_EPOCH: AtomicUsize = AtomicUsize::new(0);
+    }
+
+    weak_static! {
+        static TRACKED: Tracked = Tracked {
+            epoch: NEXT_EPOCH.fetch_add(1, Ordering::SeqCst),
+        };
+    }
+
+    /// Two threads racing `TRACKED()` must neither deadlock nor panic, and as long as
+    /// both keep their returned `Arc` alive (as this test does, only comparing once
+    /// both have joined) they must end up sharing the very same instance: whichever
+    /// call installs the weak pointer first, the other has to observe it still live
+    /// and upgrade to it, rather than the double-checked-locking dance racing and
+    /// handing out two distinct, un-deduplicated values.
+    #[test]
+    fn concurrent_access_shares_a_single_instance() {
+        loom::model(|| {
+            let handles: Vec<_> = (0..2).map(|_| thread::spawn(TRACKED)).collect();
+
+            let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+            assert_eq!(values[0].epoch, values[1].epoch, "racing calls built distinct instances");
+        });
+    }
+}
+
+// Plain, non-loom tests: the loom harness above only runs under `--cfg loom`, which a
+// regular `cargo test` never sets, so the reentrant-init fix needs its own coverage
+// with a real OS thread and `std::sync::Mutex`.
+#[cfg(all(test, not(loom), not(feature = "no_std")))]
+mod reentrant_tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Reentrant(usize);
+
+    static ENTERED: AtomicUsize = AtomicUsize::new(0);
+
+    weak_static! {
+        // The initializer calls its own accessor, which used to deadlock on the
+        // non-reentrant `std::sync::Mutex` guarding `VALUE` before the lock was
+        // dropped around `init()`.
+        static REENTRANT: Reentrant = {
+            if ENTERED.fetch_add(1, Ordering::SeqCst) == 0 {
+                let inner = REENTRANT();
+                Reentrant(inner.0 + 1)
+            } else {
+                Reentrant(0)
+            }
+        };
+    }
+
+    #[test]
+    fn reentrant_init_does_not_deadlock() {
+        let handle = std::thread::spawn(|| REENTRANT().0);
+        let value = handle.join().expect("reentrant init must not deadlock");
+        assert_eq!(value, 1);
+    }
+}
+
+#[cfg(all(test, not(loom), not(feature = "no_std")))]
+mod weak_static_map_tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    struct Cached {
+        build: u32,
+    }
+
+    static BUILDS: AtomicU32 = AtomicU32::new(0);
+
+    weak_static_map! {
+        static CACHED(_key: u32): Cached = Cached {
+            build: BUILDS.fetch_add(1, Ordering::SeqCst),
+        };
+    }
+
+    #[test]
+    fn repeated_key_shares_one_allocation() {
+        let a = CACHED(1);
+        let b = CACHED(1);
+        assert!(Arc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn entry_is_recreated_once_the_last_arc_drops() {
+        let first = CACHED(2);
+        let first_build = first.build;
+        drop(first);
+
+        let second = CACHED(2);
+        assert_ne!(second.build, first_build);
+    }
+
+    #[test]
+    fn map_prunes_dead_entries_past_the_threshold() {
+        use std::collections::HashMap;
+        use std::sync::{Mutex, Weak};
+
+        let map: Mutex<HashMap<u32, Weak<u32>>> = Mutex::new(HashMap::new());
+
+        // Every `Arc` below is dropped immediately, so by the time the map crosses
+        // `PRUNE_THRESHOLD` every entry in it is dead and the next insert should sweep
+        // all of them away rather than letting the map grow unbounded with tombstones.
+        for key in 0..=(crate::__sync::PRUNE_THRESHOLD as u32) {
+            drop(crate::__sync::get_or_init_keyed(&map, key, || key));
+        }
+
+        assert_eq!(
+            map.lock().unwrap().len(),
+            1,
+            "map should have pruned all dead entries once it crossed the threshold"
+        );
+    }
+}